@@ -4,12 +4,21 @@
  */
 
 use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
-use aws_smithy_http::url::Url;
+use bytes::Bytes;
+use http_body::Body as HttpBody;
 use tower::Layer;
 use tower::Service;
 
-use crate::body::BoxBody;
+// `form_urlencoded` is used by this module (and by the query-string routing fixed in
+// `AwsQueryRouter::match_route`) to decode `application/x-www-form-urlencoded` bytes without
+// allocating a full `Url`. It must be declared as a *direct* dependency of this crate's
+// `Cargo.toml` (a transitive one, e.g. via the `url` crate, won't resolve from `extern_prelude`).
+
+use crate::body::{to_boxed, BoxBody};
 use crate::response::IntoResponse;
 use crate::routing::method_disallowed;
 use crate::routing::tiny_map::TinyMap;
@@ -18,6 +27,7 @@ use crate::routing::Router;
 
 use thiserror::Error;
 
+use super::runtime_error::RuntimeError;
 use super::AwsQuery;
 
 /// An AWS Query routing error.
@@ -29,21 +39,89 @@ pub enum Error {
     /// Operation not found.
     #[error("operation not found")]
     NotFound,
+    /// The request's `Content-Type` header was present and not `application/x-www-form-urlencoded`.
+    #[error("unsupported media type: request does not contain the expected `Content-Type` header value")]
+    UnsupportedMediaType,
+    /// The request's `Accept` header cannot be satisfied by the XML media type this protocol returns.
+    #[error("not acceptable request: request contains an `Accept` header with a MIME type, and the server cannot return a response body adhering to that MIME type")]
+    NotAcceptable,
+    /// The request body exceeded the cap placed on it while looking for `Action`.
+    #[error("payload too large: request body exceeds the maximum size allowed")]
+    PayloadTooLarge,
 }
 
 impl IntoResponse<AwsQuery> for Error {
     fn into_response(self) -> http::Response<BoxBody> {
         match self {
-            Error::NotFound => http::Response::builder()
-                .status(http::StatusCode::NOT_FOUND)
-                .header(http::header::CONTENT_TYPE, "text/xml")
-                .body(crate::body::to_boxed("{}"))
-                .expect("invalid HTTP response for REST JSON 1 routing error; please file a bug report under https://github.com/smithy-lang/smithy-rs/issues"),
+            Error::NotFound => {
+                let body = super::runtime_error::xml_error_envelope("Sender", "NotFoundException", &self.to_string());
+                http::Response::builder()
+                    .status(http::StatusCode::NOT_FOUND)
+                    .header(http::header::CONTENT_TYPE, "text/xml")
+                    .body(to_boxed(body))
+                    .expect("invalid HTTP response for AWS Query routing error; please file a bug report under https://github.com/smithy-lang/smithy-rs/issues")
+            }
             Error::MethodNotAllowed => method_disallowed(),
+            Error::UnsupportedMediaType => IntoResponse::<AwsQuery>::into_response(RuntimeError::UnsupportedMediaType),
+            Error::NotAcceptable => IntoResponse::<AwsQuery>::into_response(RuntimeError::NotAcceptable),
+            Error::PayloadTooLarge => {
+                let body = super::runtime_error::xml_error_envelope("Sender", "PayloadTooLargeException", &self.to_string());
+                http::Response::builder()
+                    .status(http::StatusCode::PAYLOAD_TOO_LARGE)
+                    .header(http::header::CONTENT_TYPE, "text/xml")
+                    .body(to_boxed(body))
+                    .expect("invalid HTTP response for AWS Query routing error; please file a bug report under https://github.com/smithy-lang/smithy-rs/issues")
+            }
+        }
+    }
+}
+
+/// The only `Content-Type` the AWS Query protocol accepts for request bodies.
+const EXPECTED_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+
+/// Rejects `request` with [`Error::UnsupportedMediaType`] if it carries a `Content-Type` header
+/// that isn't `application/x-www-form-urlencoded`. A missing `Content-Type` is allowed through,
+/// mirroring the media-type gating already modeled for other protocols.
+fn check_content_type<B>(request: &http::Request<B>) -> Result<(), Error> {
+    if let Some(content_type) = request.headers().get(http::header::CONTENT_TYPE) {
+        let is_form_urlencoded = content_type
+            .to_str()
+            .map(|value| {
+                value
+                    .split(';')
+                    .next()
+                    .unwrap_or("")
+                    .trim()
+                    .eq_ignore_ascii_case(EXPECTED_CONTENT_TYPE)
+            })
+            .unwrap_or(false);
+        if !is_form_urlencoded {
+            return Err(Error::UnsupportedMediaType);
         }
     }
+    Ok(())
 }
 
+/// Rejects `request` with [`Error::NotAcceptable`] if it carries an `Accept` header that cannot
+/// be satisfied by the `text/xml` responses this protocol returns. A missing `Accept` header is
+/// allowed through.
+fn check_accept<B>(request: &http::Request<B>) -> Result<(), Error> {
+    if let Some(accept) = request.headers().get(http::header::ACCEPT) {
+        let is_acceptable = accept
+            .to_str()
+            .map(|value| {
+                value.split(',').any(|mime| {
+                    let mime = mime.split(';').next().unwrap_or("").trim();
+                    mime == "*/*" || mime == "text/*" || mime == "text/xml"
+                })
+            })
+            .unwrap_or(false);
+        if !is_acceptable {
+            return Err(Error::NotAcceptable);
+        }
+    }
+    Ok(())
+}
 
 // This constant determines when the `TinyMap` implementation switches from being a `Vec` to a
 // `HashMap`. This is chosen to be 15 as a result of the discussion around
@@ -94,30 +172,224 @@ where
     type Service = S;
     type Error = Error;
 
+    #[tracing::instrument(level = "debug", skip_all, fields(action = tracing::field::Empty))]
     fn match_route(&self, request: &http::Request<B>) -> Result<S, Self::Error> {
         // Only `Method::POST` is allowed.
         if request.method() != http::Method::POST {
+            tracing::warn!(error = %Error::MethodNotAllowed, "rejecting AWS Query request");
             return Err(Error::MethodNotAllowed);
         }
 
-        // The URI must be root
-        let url = Url::parse(&request.uri().to_string()).map_err(|_e| Error::NotFound)?;
+        check_content_type(request).map_err(log_rejection)?;
+        check_accept(request).map_err(log_rejection)?;
 
-        let (_, target) = url
-            .query_pairs()
-            .find(|(k, _v)| {
-                k == "Action"
-            })
-            .ok_or({
-                Error::NotFound
-            })?;
+        // Read the query string directly off the URI rather than going through `Url::parse`,
+        // which allocates a `String` on every request and, since it expects an absolute URL,
+        // fails with `NotFound` for the relative request targets (e.g. `POST /?Action=Foo`) that
+        // servers sitting behind a proxy actually receive.
+        let query = request.uri().query().ok_or(Error::NotFound).map_err(log_rejection)?;
+        let (_, action) = form_urlencoded::parse(query.as_bytes())
+            .find(|(k, _v)| k == "Action")
+            .ok_or(Error::NotFound)
+            .map_err(log_rejection)?;
+        tracing::Span::current().record("action", action.as_ref());
 
         // Lookup in the `TinyMap` for a route for the target.
-        let route = self.routes.get(target.to_string().as_str()).ok_or(Error::NotFound)?;
+        let route = self.routes.get(action.as_ref()).ok_or(Error::NotFound).map_err(log_rejection)?;
+        tracing::debug!(action = %action, "matched AWS Query operation");
         Ok(route.clone())
     }
 }
 
+/// Emits a `warn`-level event recording `error` before passing it through unchanged, so routing
+/// failures show up in the [`Router::match_route`]/[`AwsQueryRouter::match_route_with_body`] span.
+fn log_rejection(error: Error) -> Error {
+    tracing::warn!(%error, "rejecting AWS Query request");
+    error
+}
+
+/// The default cap, in bytes, placed on the request body while searching for the `Action`
+/// field. Pass a different limit to [`AwsQueryRouter::match_route_with_body`] or
+/// [`ActionFromBodyLayer::new`] to override it.
+pub const DEFAULT_ACTION_BODY_LIMIT: usize = 1024 * 1024;
+
+// The AWS Query protocol places `Action` (along with `Version` and the rest of the operation
+// input) in the form-urlencoded POST body, not in the URI query string. `Router::match_route`
+// is synchronous and is handed only a `&http::Request<B>`, so it cannot buffer and await the
+// body; the query-string lookup above exists only as a best-effort fallback for non-conformant
+// requests. `match_route_with_body` is the conformant entry point: it buffers the body (up to
+// a caller-provided cap), re-reads `Action` out of it, and hands back a reconstructed request so
+// the downstream operation deserializer can read the body again from the start. It is driven by
+// [`ActionFromBodyLayer`] below, which is how real request traffic reaches it.
+impl<S> AwsQueryRouter<S>
+where
+    S: Clone,
+{
+    /// Resolves the route for `request` by parsing `Action` out of its form-urlencoded body,
+    /// falling back to the `Action` query string parameter when the body doesn't contain one.
+    /// At most `body_limit` bytes of the body are buffered while searching; a body exceeding
+    /// that cap is rejected with [`Error::PayloadTooLarge`] rather than treated as empty.
+    ///
+    /// Unlike [`Router::match_route`], this buffers the entire request body, so the returned
+    /// request carries the buffered bytes reattached as its body; callers must use this request
+    /// (rather than the original one) downstream so the operation deserializer can still read it.
+    #[tracing::instrument(level = "debug", skip_all, fields(action = tracing::field::Empty))]
+    pub async fn match_route_with_body<B>(
+        &self,
+        request: http::Request<B>,
+        body_limit: usize,
+    ) -> Result<(S, http::Request<BoxBody>), Error>
+    where
+        B: HttpBody<Data = Bytes> + Send + 'static,
+        B::Error: std::fmt::Display,
+    {
+        if request.method() != http::Method::POST {
+            tracing::warn!(error = %Error::MethodNotAllowed, "rejecting AWS Query request");
+            return Err(Error::MethodNotAllowed);
+        }
+
+        check_content_type(&request).map_err(log_rejection)?;
+        check_accept(&request).map_err(log_rejection)?;
+
+        let (parts, body) = request.into_parts();
+        // An oversized body must not be silently treated as an empty one: that would either
+        // dispatch the matched operation with none of the client's actual input (if `Action`
+        // still resolves via the query-string fallback) or misreport a legitimate oversized
+        // request as `NotFound`. Reject it distinctly instead.
+        let body_bytes = match buffer_body_with_limit(body, body_limit).await {
+            Ok(bytes) => bytes,
+            Err(BufferBodyError::TooLarge) => return Err(log_rejection(Error::PayloadTooLarge)),
+            Err(BufferBodyError::Read(reason)) => {
+                tracing::warn!(%reason, "failed to read AWS Query request body; falling back to the query string");
+                Bytes::new()
+            }
+        };
+
+        let action = form_urlencoded::parse(&body_bytes)
+            .find(|(k, _v)| k == "Action")
+            .map(|(_k, v)| v.into_owned())
+            .or_else(|| {
+                parts.uri.query().and_then(|query| {
+                    form_urlencoded::parse(query.as_bytes())
+                        .find(|(k, _v)| k == "Action")
+                        .map(|(_k, v)| v.into_owned())
+                })
+            })
+            .ok_or(Error::NotFound)
+            .map_err(log_rejection)?;
+        tracing::Span::current().record("action", action.as_str());
+
+        let route = self
+            .routes
+            .get(action.as_str())
+            .ok_or(Error::NotFound)
+            .map_err(log_rejection)?
+            .clone();
+        tracing::debug!(%action, "matched AWS Query operation");
+        let request = http::Request::from_parts(parts, to_boxed(body_bytes));
+
+        Ok((route, request))
+    }
+}
+
+/// A [`Layer`] that makes an [`AwsQueryRouter`] dispatch real requests by parsing `Action` out of
+/// their form-urlencoded POST body (falling back to the query string), via
+/// [`AwsQueryRouter::match_route_with_body`], instead of only ever consulting the query string.
+/// Apply it directly to a (pre-[`AwsQueryRouter::boxed`]) router to get the [`ActionFromBodyService`]
+/// that generated servers should serve traffic through.
+#[derive(Debug, Clone)]
+pub struct ActionFromBodyLayer {
+    body_limit: usize,
+}
+
+impl ActionFromBodyLayer {
+    /// Creates a layer that buffers at most `body_limit` bytes of the request body while
+    /// looking for `Action`.
+    pub fn new(body_limit: usize) -> Self {
+        Self { body_limit }
+    }
+}
+
+impl Default for ActionFromBodyLayer {
+    /// Uses [`DEFAULT_ACTION_BODY_LIMIT`] as the body cap.
+    fn default() -> Self {
+        Self::new(DEFAULT_ACTION_BODY_LIMIT)
+    }
+}
+
+impl<S> Layer<AwsQueryRouter<S>> for ActionFromBodyLayer {
+    type Service = ActionFromBodyService<S>;
+
+    fn layer(&self, inner: AwsQueryRouter<S>) -> Self::Service {
+        ActionFromBodyService {
+            router: inner,
+            body_limit: self.body_limit,
+        }
+    }
+}
+
+/// The [`Service`] produced by [`ActionFromBodyLayer`]; see its docs.
+#[derive(Debug, Clone)]
+pub struct ActionFromBodyService<S> {
+    router: AwsQueryRouter<S>,
+    body_limit: usize,
+}
+
+impl<B, S> Service<http::Request<B>> for ActionFromBodyService<S>
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: std::fmt::Display,
+    S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let router = self.router.clone();
+        let body_limit = self.body_limit;
+        Box::pin(async move {
+            match router.match_route_with_body(request, body_limit).await {
+                Ok((mut route, request)) => route.call(request).await,
+                Err(error) => Ok(IntoResponse::<AwsQuery>::into_response(error)),
+            }
+        })
+    }
+}
+
+/// Why [`buffer_body_with_limit`] couldn't produce the buffered body.
+enum BufferBodyError {
+    /// The body exceeded the configured cap. Distinct from [`Self::Read`] so callers can reject
+    /// the request instead of silently substituting an empty body for a real, truncated-away one.
+    TooLarge,
+    /// The underlying body stream returned an error while being read.
+    Read(String),
+}
+
+/// Buffers `body` into a single [`Bytes`], failing if it can't be read or it exceeds `limit`
+/// bytes.
+async fn buffer_body_with_limit<B>(mut body: B, limit: usize) -> Result<Bytes, BufferBodyError>
+where
+    B: HttpBody<Data = Bytes> + Send,
+    B::Error: std::fmt::Display,
+{
+    let mut buf = Vec::new();
+    futures_util::pin_mut!(body);
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| BufferBodyError::Read(err.to_string()))?;
+        if buf.len() + chunk.len() > limit {
+            return Err(BufferBodyError::TooLarge);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(buf))
+}
+
 impl<S> FromIterator<(&'static str, S)> for AwsQueryRouter<S> {
     #[inline]
     fn from_iter<T: IntoIterator<Item = (&'static str, S)>>(iter: T) -> Self {
@@ -157,4 +429,94 @@ mod tests {
         let res = router.match_route(&req(&Method::GET, "/", None));
         assert_eq!(res.unwrap_err().to_string(), Error::MethodNotAllowed.to_string());
     }
+
+    #[tokio::test]
+    async fn routes_relative_uris() {
+        let routes = vec![("Service.Operation")];
+        let router: AwsQueryRouter<_> = routes.clone().into_iter().map(|operation| (operation, ())).collect();
+
+        // A relative request target, as servers sitting behind a proxy actually receive, should match.
+        router
+            .match_route(&req(&Method::POST, "/?Action=Service.Operation", None))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn body_routing_reads_action_from_form_encoded_body() {
+        let routes = vec![("Service.Operation")];
+        let router: AwsQueryRouter<_> = routes.clone().into_iter().map(|operation| (operation, ())).collect();
+
+        // `Action` is in the body, as real AWS Query clients send it.
+        let request = req(&Method::POST, "/", Some("Action=Service.Operation&Version=2020-01-01"));
+        router.match_route_with_body(request, DEFAULT_ACTION_BODY_LIMIT).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn body_routing_falls_back_to_query_string() {
+        let routes = vec![("Service.Operation")];
+        let router: AwsQueryRouter<_> = routes.clone().into_iter().map(|operation| (operation, ())).collect();
+
+        // No `Action` in the body, but it's present in the query string.
+        let request = req(&Method::POST, "/?Action=Service.Operation", None);
+        router.match_route_with_body(request, DEFAULT_ACTION_BODY_LIMIT).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn body_routing_rejects_oversized_body_instead_of_treating_it_as_empty() {
+        let routes = vec![("Service.Operation")];
+        let router: AwsQueryRouter<_> = routes.clone().into_iter().map(|operation| (operation, ())).collect();
+
+        // Even though `Action` is also present in the query string, a body that exceeds the cap
+        // must be rejected rather than silently dispatched with an empty body.
+        let request = req(&Method::POST, "/?Action=Service.Operation", Some("Action=Service.Operation"));
+        let res = router.match_route_with_body(request, 1).await;
+        assert_eq!(res.unwrap_err().to_string(), Error::PayloadTooLarge.to_string());
+    }
+
+    #[tokio::test]
+    async fn action_from_body_layer_dispatches_real_traffic_by_body_action() {
+        // This is the path real request traffic takes: `ActionFromBodyLayer` wraps the router
+        // and is itself the `Service` that gets called, so a request whose `Action` only lives
+        // in the body (the conformant, common case) must still reach the matched operation.
+        let routes = vec![(
+            "Service.Operation",
+            tower::service_fn(|_req: http::Request<BoxBody>| async move {
+                Ok::<_, Infallible>(http::Response::new(to_boxed("matched")))
+            }),
+        )];
+        let router: AwsQueryRouter<_> = routes.into_iter().collect();
+        let mut service = ActionFromBodyLayer::default().layer(router);
+
+        let request = req(&Method::POST, "/", Some("Action=Service.Operation&Version=2020-01-01"));
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_content_type() {
+        let routes = vec![("Service.Operation")];
+        let router: AwsQueryRouter<_> = routes.clone().into_iter().map(|operation| (operation, ())).collect();
+
+        let mut request = req(&Method::POST, "/?Action=Service.Operation", None);
+        request
+            .headers_mut()
+            .insert(http::header::CONTENT_TYPE, http::HeaderValue::from_static("application/json"));
+
+        let res = router.match_route(&request);
+        assert_eq!(res.unwrap_err().to_string(), Error::UnsupportedMediaType.to_string());
+    }
+
+    #[tokio::test]
+    async fn rejects_unsatisfiable_accept() {
+        let routes = vec![("Service.Operation")];
+        let router: AwsQueryRouter<_> = routes.clone().into_iter().map(|operation| (operation, ())).collect();
+
+        let mut request = req(&Method::POST, "/?Action=Service.Operation", None);
+        request
+            .headers_mut()
+            .insert(http::header::ACCEPT, http::HeaderValue::from_static("application/json"));
+
+        let res = router.match_route(&request);
+        assert_eq!(res.unwrap_err().to_string(), Error::NotAcceptable.to_string());
+    }
 }
@@ -58,19 +58,65 @@ impl IntoResponse<AwsQuery> for InternalFailureException {
     }
 }
 
+/// The `Type` an AWS Query error envelope reports, per the protocol's error model:
+/// <https://smithy.io/2.0/aws/protocols/aws-query-protocol.html#query-protocol-errors>
+/// Client-caused failures are `Sender`, server-caused failures are `Receiver`.
+enum ErrorType {
+    Sender,
+    Receiver,
+}
+
+impl ErrorType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ErrorType::Sender => "Sender",
+            ErrorType::Receiver => "Receiver",
+        }
+    }
+}
+
+/// Escapes the characters in `value` that are not legal inside XML text content.
+fn escape_xml(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Renders a spec-compliant AWS Query XML error envelope:
+/// `<ErrorResponse><Error><Type>...</Type><Code>...</Code><Message>...</Message></Error><RequestId>...</RequestId></ErrorResponse>`
+/// The `<RequestId>` element is always written empty.
+pub(crate) fn xml_error_envelope(error_type: &str, code: &str, message: &str) -> String {
+    format!(
+        "<ErrorResponse><Error><Type>{error_type}</Type><Code>{code}</Code><Message>{message}</Message></Error><RequestId></RequestId></ErrorResponse>",
+        error_type = error_type,
+        code = code,
+        message = escape_xml(message),
+    )
+}
+
 impl IntoResponse<AwsQuery> for RuntimeError {
     fn into_response(self) -> http::Response<crate::body::BoxBody> {
-        let res = http::Response::builder()
-            .status(self.status_code())
-            .header("Content-Type", "application/x-amz-json-1.1")
-            .extension(RuntimeErrorExtension::new(self.name().to_string()));
-
-        let body = match self {
-            RuntimeError::Validation(reason) => crate::body::to_boxed(reason),
-            _ => crate::body::to_boxed(""),
+        let error_type = match self {
+            RuntimeError::InternalFailure(_) => ErrorType::Receiver,
+            RuntimeError::Serialization(_) | RuntimeError::NotAcceptable | RuntimeError::UnsupportedMediaType | RuntimeError::Validation(_) => {
+                ErrorType::Sender
+            }
+        };
+        // `InternalFailure` wraps an arbitrary, potentially sensitive `crate::Error` (handler
+        // panics, backend/connection failures, ...); never put its `Display` output in a
+        // client-visible `<Message>`. The other variants' text is surfaced back to the client;
+        // `Serialization` is reported as `Sender` and its text surfaced even when it wraps a
+        // `ResponseRejection` (a response that failed to serialize).
+        let message = match &self {
+            RuntimeError::InternalFailure(_) => "internal error".to_owned(),
+            RuntimeError::Validation(reason) => reason.clone(),
+            RuntimeError::Serialization(_) | RuntimeError::NotAcceptable | RuntimeError::UnsupportedMediaType => self.to_string(),
         };
+        let body = xml_error_envelope(error_type.as_str(), self.name(), &message);
 
-        res.body(body)
+        http::Response::builder()
+            .status(self.status_code())
+            .header(http::header::CONTENT_TYPE, "text/xml")
+            .extension(RuntimeErrorExtension::new(self.name().to_string()))
+            .body(crate::body::to_boxed(body))
             .expect(INVALID_HTTP_RESPONSE_FOR_RUNTIME_ERROR_PANIC_MESSAGE)
     }
 }
@@ -89,3 +135,48 @@ impl From<RequestRejection> for RuntimeError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body::Body as HttpBody;
+    use pretty_assertions::assert_eq;
+
+    async fn body_string(response: http::Response<crate::body::BoxBody>) -> String {
+        let mut body = response.into_body();
+        let mut buf = Vec::new();
+        futures_util::pin_mut!(body);
+        while let Some(chunk) = body.data().await {
+            buf.extend_from_slice(&chunk.expect("test body is infallible"));
+        }
+        String::from_utf8(buf).expect("test body is UTF-8")
+    }
+
+    #[tokio::test]
+    async fn validation_error_renders_sender_envelope_with_escaped_message() {
+        let error = RuntimeError::Validation("bad <value> & stuff".to_owned());
+        let response = IntoResponse::<AwsQuery>::into_response(error);
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers().get(http::header::CONTENT_TYPE).unwrap(), "text/xml");
+
+        let body = body_string(response).await;
+        assert!(body.contains("<Type>Sender</Type>"), "body was: {body}");
+        assert!(body.contains("<Code>ValidationException</Code>"), "body was: {body}");
+        assert!(body.contains("<Message>bad &lt;value&gt; &amp; stuff</Message>"), "body was: {body}");
+    }
+
+    #[tokio::test]
+    async fn internal_failure_renders_receiver_envelope_without_leaking_details() {
+        let error = RuntimeError::InternalFailure(crate::Error::new("do not leak this".to_owned()));
+        let response = IntoResponse::<AwsQuery>::into_response(error);
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.headers().get(http::header::CONTENT_TYPE).unwrap(), "text/xml");
+
+        let body = body_string(response).await;
+        assert!(body.contains("<Type>Receiver</Type>"), "body was: {body}");
+        assert!(body.contains("<Code>InternalFailureException</Code>"), "body was: {body}");
+        assert!(!body.contains("do not leak this"), "body leaked internal error details: {body}");
+    }
+}